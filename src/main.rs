@@ -1,8 +1,12 @@
-use std::{fs, error, path, process, io};
+use std::{fs, error, path, io};
 use std::io::Read;
 
+use chrono::Datelike;
 use clap;
-use serde::Deserialize;
+use filetime;
+use libc;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
 
 fn main() {
     let matches = clap::App::new("backup")
@@ -14,6 +18,38 @@ fn main() {
                 .takes_value(true)
                 .default_value("~/backupkern.yaml")
         )
+        .subcommand(
+            clap::SubCommand::with_name("restore")
+                .about("Restores a snapshot to an output directory")
+                .arg(
+                    clap::Arg::with_name("snapshot")
+                        .value_name("SNAPSHOT")
+                        .help("Name of the snapshot directory to restore, or \"latest\"")
+                        .required(true)
+                )
+                .arg(
+                    clap::Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .value_name("DIR")
+                        .help("Directory to restore the snapshot into")
+                        .takes_value(true)
+                        .required(true)
+                )
+        )
+        .subcommand(
+            clap::SubCommand::with_name("list")
+                .about("Lists snapshots in the configured backup locations and their sizes")
+        )
+        .subcommand(
+            clap::SubCommand::with_name("prune")
+                .about("Deletes old snapshots according to the configured retention policy")
+                .arg(
+                    clap::Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Print what would be deleted without deleting anything")
+                )
+        )
         .get_matches();
 
     let path = matches.value_of("configpath").expect("Config path is required.");
@@ -30,11 +66,33 @@ fn main() {
         return;
     }
 
-    println!("{:#?}", config);
+    match matches.subcommand() {
+        ("restore", Some(sub_matches)) => {
+            let snapshot = sub_matches.value_of("snapshot").expect("Snapshot name is required.");
+            let output = sub_matches.value_of("output").expect("Output directory is required.");
+            if let Err(e) = run_restore(&config, snapshot, output) {
+                println!("{}", e);
+            }
+        },
+        ("list", Some(_)) => {
+            if let Err(e) = run_list(&config) {
+                println!("{}", e);
+            }
+        },
+        ("prune", Some(sub_matches)) => {
+            let dry_run = sub_matches.is_present("dry-run");
+            if let Err(e) = run_prune(&config, dry_run) {
+                println!("{}", e);
+            }
+        },
+        _ => {
+            println!("{:#?}", config);
 
-    // Start copying
-    if let Err(e) = run_backup(&config) {
-        println!("{}", e);
+            // Start copying
+            if let Err(e) = run_backup(&config) {
+                println!("{}", e);
+            }
+        },
     }
 
 }
@@ -45,11 +103,35 @@ struct Config {
     to: Vec<String>,
     prefix: String,
     exclude: ExcludeOptions,
+    #[serde(default)]
+    retention: RetentionConfig,
+    #[serde(default)]
+    verify_contents: bool,
 }
 #[derive(Deserialize, Debug)]
 struct ExcludeOptions {
     locations: Vec<String>,
 }
+#[derive(Deserialize, Debug, Default)]
+struct RetentionConfig {
+    #[serde(default)]
+    keep_last: usize,
+    #[serde(default)]
+    keep_daily: usize,
+    #[serde(default)]
+    keep_weekly: usize,
+    #[serde(default)]
+    keep_monthly: usize,
+}
+impl RetentionConfig {
+    /// A `retention` section where every tier is 0 (including a missing
+    /// section, since every field defaults to 0) keeps nothing, which would
+    /// make `prune` delete every snapshot. Treat that as "no policy
+    /// configured" rather than "keep nothing".
+    fn is_unconfigured(&self) -> bool {
+        self.keep_last == 0 && self.keep_daily == 0 && self.keep_weekly == 0 && self.keep_monthly == 0
+    }
+}
 impl Config {
     /// Returns true if the file should not be backed up.
     fn ignore(&self, f: &path::PathBuf) -> bool {
@@ -62,6 +144,36 @@ impl Config {
     }
 }
 
+/// Metadata about a single backup run, written to `manifest.yaml` in the
+/// snapshot directory once the backup completes.
+#[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    source: String,
+    hostname: String,
+    start_time: chrono::DateTime<chrono::Local>,
+    end_time: chrono::DateTime<chrono::Local>,
+    file_count: u64,
+    total_bytes: u64,
+    linked_count: u64,
+    copied_count: u64,
+}
+
+/// Returns the local machine's hostname, or `"unknown"` if it could not be
+/// determined.
+fn hostname() -> String {
+    let mut buffer = vec![0u8; 256];
+    let result = unsafe {
+        libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len())
+    };
+
+    if result != 0 {
+        return String::from("unknown");
+    }
+
+    let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..end]).into_owned()
+}
+
 fn read_config(path: &str) -> Result<Config, Box<error::Error>> {
 
     let mut file = fs::File::open(path)?;
@@ -83,6 +195,7 @@ fn get_latest_backup(backup_root: &str) -> Option<path::PathBuf> {
     let mut all_old_dirs: Vec<path::PathBuf> = all_old_dirs
         .filter(|e| e.is_ok())
         .map(|e| e.unwrap().path())
+        .filter(|p| p.file_name().map_or(false, |n| n != ".index"))
         .collect();
 
     all_old_dirs.sort();
@@ -98,7 +211,9 @@ fn get_latest_backup(backup_root: &str) -> Option<path::PathBuf> {
 
 /// Compares two paths. If the are not *files* with the same name,
 /// this returns false. They will then be compared by size and contents
-fn files_equal(a: &path::PathBuf, b: &path::PathBuf) -> bool {
+/// (contents only when `verify_contents` is true); permissions and mtime
+/// must match as well.
+fn files_equal(a: &path::PathBuf, b: &path::PathBuf, verify_contents: bool) -> bool {
     if a.file_name() != b.file_name() {
         return false;
     }
@@ -109,6 +224,8 @@ fn files_equal(a: &path::PathBuf, b: &path::PathBuf) -> bool {
 
     match (a.metadata(), b.metadata()) {
         (Ok(a_meta), Ok(b_meta)) => {
+            // Differing sizes can never be equal, so rule that out before
+            // touching either file's contents.
             if a_meta.len() != b_meta.len() {
                 return false;
             }
@@ -117,24 +234,20 @@ fn files_equal(a: &path::PathBuf, b: &path::PathBuf) -> bool {
             }
             match (a_meta.modified(), b_meta.modified()) {
                 (Ok(a_time), Ok(b_time)) => {
-                    return a_time == b_time;
+                    if a_time != b_time {
+                        return false;
+                    }
                 },
                 _ => {
                     return false;
                 }
             }
 
-            // TODO Compare file contents if you set a flag
-            // This is an implementation of my lazyness. It would be faster to read
-            // small chunks of both files and compare them.
-//            match (fs::read(a), fs::read(b)) {
-//                (Ok(a_content), Ok(b_content)) => {
-//                    return md5::compute(a_content) == md5::compute(b_content);
-//                },
-//                _ => {
-//                    return false;
-//                },
-//            }
+            if verify_contents {
+                return contents_equal(a, b);
+            }
+
+            true
         },
         _ => {
             return false;
@@ -142,37 +255,161 @@ fn files_equal(a: &path::PathBuf, b: &path::PathBuf) -> bool {
     }
 }
 
+/// Streams both files in fixed-size chunks and compares them byte for byte.
+/// Only called once the cheap metadata checks in `files_equal` already
+/// agree, since this is the expensive path.
+fn contents_equal(a: &path::Path, b: &path::Path) -> bool {
+    let (mut file_a, mut file_b) = match (fs::File::open(a), fs::File::open(b)) {
+        (Ok(file_a), Ok(file_b)) => (file_a, file_b),
+        _ => return false,
+    };
+
+    let mut buf_a = [0u8; 65536];
+    let mut buf_b = [0u8; 65536];
+
+    loop {
+        let read_a = match fill_buf(&mut file_a, &mut buf_a) {
+            Ok(n) => n,
+            Err(_) => return false,
+        };
+        let read_b = match fill_buf(&mut file_b, &mut buf_b) {
+            Ok(n) => n,
+            Err(_) => return false,
+        };
+
+        if read_a != read_b {
+            return false;
+        }
+        if read_a == 0 {
+            return true;
+        }
+        if buf_a[..read_a] != buf_b[..read_b] {
+            return false;
+        }
+    }
+}
+
+/// Fills `buf` from `file` as far as possible, returning fewer bytes than
+/// `buf.len()` only at EOF. A single `Read::read` call is not guaranteed to
+/// fill the buffer (e.g. on network filesystems), so `contents_equal` needs
+/// this to avoid comparing misaligned chunks from the two files.
+fn fill_buf(file: &mut fs::File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        let read = file.read(&mut buf[total..])?;
+        if read == 0 {
+            break;
+        }
+        total += read;
+    }
+    Ok(total)
+}
+
+/// Copies a file from `copy_from` to `copy_to` and restores its permissions
+/// and modification/access time on the destination, the way `cp -p` would,
+/// but without shelling out so this also works on Windows.
 fn cp(copy_from: &path::Path, copy_to: &path::Path) -> io::Result<()> {
-    // Use cp -p to preserve timestamps and permissions
-    process::Command::new("cp").arg("-p").arg(copy_from).arg(copy_to).output()?;
+    fs::copy(copy_from, copy_to)?;
+
+    let metadata = fs::metadata(copy_from)?;
+    fs::set_permissions(copy_to, metadata.permissions())?;
+
+    let atime = filetime::FileTime::from_last_access_time(&metadata);
+    let mtime = filetime::FileTime::from_last_modification_time(&metadata);
+    filetime::set_file_times(copy_to, atime, mtime)?;
+
     Ok(())
 }
 
-fn copy_file(copy_from: &path::Path, copy_to: &path::Path, suffix: &path::Path, latest_backup: &Option<path::PathBuf>) -> Result<(), Box<error::Error>> {
+/// Computes the SHA-256 hash of a file's contents, as a lowercase hex string.
+fn hash_file(path: &path::Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = sha2::Sha256::new();
+    let mut buffer = [0u8; 65536];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.input(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// Returns where the `variant`-th file with the given content hash is (or
+/// would be) stored in the content-addressable index, sharded by the first
+/// two hex digits so a single directory never ends up with one entry per
+/// backed-up file. Variants beyond 0 exist because two files can have
+/// identical content but different permissions or mtime; since hard-linking
+/// collapses a path onto one inode, each distinct metadata combination for
+/// a given hash needs its own stored copy.
+fn index_path(index_root: &path::Path, hash: &str, variant: u32) -> path::PathBuf {
+    let name = if variant == 0 { hash.to_string() } else { format!("{}-{}", hash, variant) };
+    index_root.join(&hash[0..2]).join(name)
+}
+
+/// Finds the index slot for `hash` whose stored metadata (permissions and
+/// mtime) matches `source_meta`, creating a new variant slot if every
+/// existing one belongs to a file with different metadata. Mirrors the
+/// matching `files_equal` already requires for the same-path fast path, so
+/// content-addressed dedup never silently hands a restored file someone
+/// else's permissions or mtime.
+fn find_index_slot(index_root: &path::Path, hash: &str, source_meta: &fs::Metadata) -> io::Result<path::PathBuf> {
+    let mut variant = 0;
+    loop {
+        let candidate = index_path(index_root, hash, variant);
+        match fs::metadata(&candidate) {
+            Ok(stored_meta) => {
+                if stored_meta.permissions() == source_meta.permissions()
+                    && stored_meta.modified()? == source_meta.modified()? {
+                    return Ok(candidate);
+                }
+                variant += 1;
+            },
+            Err(_) => return Ok(candidate),
+        }
+    }
+}
+
+/// Copies or links a single file into the snapshot. Returns `true` if the
+/// file was hard-linked to an existing copy (no bytes written), or `false`
+/// if it was freshly copied.
+fn copy_file(copy_from: &path::Path, copy_to: &path::Path, suffix: &path::Path, latest_backup: &Option<path::PathBuf>, index_root: &path::Path, verify_contents: bool) -> Result<bool, Box<error::Error>> {
     println!("{:?}", copy_from);
 
-    match latest_backup {
-        Some(backup) => {
-            // Find previous version of file
-            let previous_version = backup.join(suffix).to_path_buf();
-            if files_equal(&previous_version, &copy_from.to_path_buf()) {
-                fs::hard_link(previous_version, copy_to)?;
-            } else {
-                cp(&copy_from, &copy_to)?;
-            }
-            Ok(())
-        },
-        None => {
-            cp(&copy_from, &copy_to)?;
-            Ok(())
+    if let Some(backup) = latest_backup {
+        // Find previous version of file at the same path
+        let previous_version = backup.join(suffix).to_path_buf();
+        if files_equal(&previous_version, &copy_from.to_path_buf(), verify_contents) {
+            fs::hard_link(previous_version, copy_to)?;
+            return Ok(true);
         }
     }
 
+    // No identical file at the same path in the previous backup. Fall back
+    // to a content-addressed lookup so files that were renamed, moved, or
+    // duplicated elsewhere in the tree are still deduplicated.
+    let hash = hash_file(copy_from)?;
+    let source_meta = fs::metadata(copy_from)?;
+    let stored = find_index_slot(index_root, &hash, &source_meta)?;
+
+    if stored.is_file() {
+        fs::hard_link(&stored, copy_to)?;
+        Ok(true)
+    } else {
+        cp(&copy_from, &copy_to)?;
+        if let Some(p) = stored.parent() {
+            fs::create_dir_all(p)?;
+        }
+        fs::hard_link(&copy_to, &stored)?;
+        Ok(false)
+    }
 }
 
 fn run_backup(config: &Config) -> Result<(), Box<error::Error>> {
 
-    let pattern = chrono::Local::now().format(&format!("{}_%Y-%m-%d_%H-%M-%S", &config.prefix)).to_string();
+    let start_time = chrono::Local::now();
+    let pattern = start_time.format(&format!("{}_%Y-%m-%d_%H-%M-%S", &config.prefix)).to_string();
 
     if config.to.len() == 0 {
         return Err(Box::new(io::Error::new(io::ErrorKind::NotFound, "No locations to backup to.")));
@@ -190,11 +427,18 @@ fn run_backup(config: &Config) -> Result<(), Box<error::Error>> {
     let to_root = path::Path::new(to_root).join(pattern);
     let from_root = &config.from;
     let latest_backup = get_latest_backup(&config.to[0]);
+    let index_root = to_root.parent().expect("to_root should have a parent").join(".index");
+    fs::create_dir_all(&index_root)?;
 
     println!("get_latest_backup: {:?}", latest_backup);
 
     println!("Backup running. to_root = {:?}, from_root = {:?}", to_root, from_root);
 
+    let mut file_count: u64 = 0;
+    let mut total_bytes: u64 = 0;
+    let mut linked_count: u64 = 0;
+    let mut copied_count: u64 = 0;
+
     for file_entry in walkdir::WalkDir::new(&config.from).min_depth(1) {
         match file_entry {
             Ok(entry) => {
@@ -213,8 +457,19 @@ fn run_backup(config: &Config) -> Result<(), Box<error::Error>> {
                 if let Some(p) = copy_to.parent() {
                     fs::create_dir_all(p)?;
                 }
-                if let Err(err) = copy_file(&copy_from, &copy_to, &suffix, &latest_backup) {
-                    println!("{}", err);
+
+                let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                match copy_file(&copy_from, &copy_to, &suffix, &latest_backup, &index_root, config.verify_contents) {
+                    Ok(linked) => {
+                        file_count += 1;
+                        total_bytes += size;
+                        if linked {
+                            linked_count += 1;
+                        } else {
+                            copied_count += 1;
+                        }
+                    },
+                    Err(err) => println!("{}", err),
                 }
 
             },
@@ -222,5 +477,308 @@ fn run_backup(config: &Config) -> Result<(), Box<error::Error>> {
         }
     }
 
+    let manifest = Manifest {
+        source: config.from.clone(),
+        hostname: hostname(),
+        start_time,
+        end_time: chrono::Local::now(),
+        file_count,
+        total_bytes,
+        linked_count,
+        copied_count,
+    };
+    fs::write(to_root.join("manifest.yaml"), serde_yaml::to_string(&manifest)?)?;
+
+    Ok(())
+}
+
+/// Finds the snapshot directory named `name` in one of the configured `to`
+/// locations, or the most recent snapshot if `name` is `"latest"`.
+fn find_snapshot(config: &Config, name: &str) -> Option<path::PathBuf> {
+    for t in &config.to {
+        if !path::Path::new(t).is_dir() {
+            continue;
+        }
+
+        let snapshot = if name == "latest" {
+            get_latest_backup(t)
+        } else {
+            let candidate = path::Path::new(t).join(name);
+            if candidate.is_dir() { Some(candidate) } else { None }
+        };
+
+        if snapshot.is_some() {
+            return snapshot;
+        }
+    }
+    None
+}
+
+/// Recreates a snapshot into `output`, preserving timestamps and permissions
+/// as `run_backup` does. `output` is always an explicit argument rather than
+/// `config.from`, so a restore never clobbers the live source by accident.
+fn run_restore(config: &Config, snapshot_name: &str, output: &str) -> Result<(), Box<error::Error>> {
+    let snapshot = find_snapshot(config, snapshot_name).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("No snapshot named '{}' found.", snapshot_name))
+    })?;
+
+    let output_root = path::Path::new(output);
+    fs::create_dir_all(output_root)?;
+
+    println!("Restoring {:?} to {:?}", snapshot, output_root);
+
+    for file_entry in walkdir::WalkDir::new(&snapshot).min_depth(1) {
+        let entry = file_entry?;
+        let suffix = entry.path().strip_prefix(&snapshot)?;
+
+        // manifest.yaml is a backup-internal artifact written by run_backup,
+        // not part of the backed-up source tree, so don't restore it.
+        if suffix == path::Path::new("manifest.yaml") {
+            continue;
+        }
+
+        let restore_to = output_root.join(suffix);
+
+        if entry.path().is_dir() {
+            fs::create_dir_all(&restore_to)?;
+            continue;
+        }
+
+        if let Some(p) = restore_to.parent() {
+            fs::create_dir_all(p)?;
+        }
+
+        cp(entry.path(), &restore_to)?;
+    }
+
+    Ok(())
+}
+
+/// Reads the `manifest.yaml` written by `run_backup` for a snapshot.
+fn read_manifest(snapshot: &path::Path) -> Result<Manifest, Box<error::Error>> {
+    let mut file = fs::File::open(snapshot.join("manifest.yaml"))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let manifest = serde_yaml::from_str(&contents)?;
+    Ok(manifest)
+}
+
+/// Formats a byte count as a human-readable size, e.g. `"12.3 MiB"`.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Lists the snapshots found under each configured `to` location, with
+/// their timestamp, duration, and total size where a manifest is available.
+fn run_list(config: &Config) -> Result<(), Box<error::Error>> {
+    for t in &config.to {
+        let root = path::Path::new(t);
+        if !root.is_dir() {
+            continue;
+        }
+
+        println!("{}:", t);
+
+        let mut snapshots: Vec<path::PathBuf> = fs::read_dir(root)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_dir() && p.file_name().map_or(false, |n| n != ".index"))
+            .collect();
+        snapshots.sort();
+
+        for snapshot in snapshots {
+            let name = snapshot.file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+
+            match read_manifest(&snapshot) {
+                Ok(manifest) => {
+                    let duration = manifest.end_time.signed_duration_since(manifest.start_time);
+                    println!(
+                        "  {}  started {}  took {}s  {} files  {}",
+                        name,
+                        manifest.start_time.format("%Y-%m-%d %H:%M:%S"),
+                        duration.num_seconds(),
+                        manifest.file_count,
+                        format_size(manifest.total_bytes),
+                    );
+                },
+                Err(_) => println!("  {}  (no manifest)", name),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds snapshot directories directly under `root` whose name matches the
+/// `<prefix>_%Y-%m-%d_%H-%M-%S` pattern produced by `run_backup`, together
+/// with the timestamp parsed from their name.
+fn list_snapshots(root: &path::Path, prefix: &str) -> io::Result<Vec<(path::PathBuf, chrono::NaiveDateTime)>> {
+    let mut snapshots = Vec::new();
+
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(n) => n,
+            None => continue,
+        };
+        let timestamp = match name.strip_prefix(&format!("{}_", prefix)) {
+            Some(t) => t,
+            None => continue,
+        };
+
+        if let Ok(dt) = chrono::NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d_%H-%M-%S") {
+            snapshots.push((path, dt));
+        }
+    }
+
+    Ok(snapshots)
+}
+
+/// Keeps the newest snapshot in each of the first `limit` distinct buckets
+/// (as produced by `key_fn`), walking `snapshots` which must be sorted
+/// newest-first.
+fn keep_by_bucket<K: Eq + std::hash::Hash>(
+    snapshots: &[(path::PathBuf, chrono::NaiveDateTime)],
+    limit: usize,
+    keep: &mut std::collections::HashSet<path::PathBuf>,
+    key_fn: impl Fn(&chrono::NaiveDateTime) -> K,
+) {
+    let mut seen_buckets = std::collections::HashSet::new();
+
+    for (path, dt) in snapshots {
+        if seen_buckets.len() >= limit {
+            break;
+        }
+
+        let key = key_fn(dt);
+        if seen_buckets.insert(key) {
+            keep.insert(path.clone());
+        }
+    }
+}
+
+/// Applies `retention` to `snapshots` (sorted newest-first) and returns the
+/// set of snapshot paths that should survive.
+fn select_snapshots_to_keep(snapshots: &[(path::PathBuf, chrono::NaiveDateTime)], retention: &RetentionConfig) -> std::collections::HashSet<path::PathBuf> {
+    let mut keep = std::collections::HashSet::new();
+
+    for (path, _) in snapshots.iter().take(retention.keep_last) {
+        keep.insert(path.clone());
+    }
+
+    keep_by_bucket(snapshots, retention.keep_daily, &mut keep, |dt| dt.date());
+    keep_by_bucket(snapshots, retention.keep_weekly, &mut keep, |dt| (dt.iso_week().year(), dt.iso_week().week()));
+    keep_by_bucket(snapshots, retention.keep_monthly, &mut keep, |dt| (dt.year(), dt.month()));
+
+    keep
+}
+
+/// Deletes snapshots not selected by the configured retention policy,
+/// generational (grandfather-father-son) style, then garbage-collects the
+/// content-addressable index via `gc_index`. Snapshots within a `to`
+/// location share file contents through hard links (both between
+/// snapshots and via the content-addressable index), so deleting an old
+/// snapshot, and then its now-unreferenced index entries, only frees the
+/// blocks no surviving snapshot still links to; it never corrupts a kept
+/// one.
+fn run_prune(config: &Config, dry_run: bool) -> Result<(), Box<error::Error>> {
+    if config.retention.is_unconfigured() {
+        return Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No retention policy configured (keep_last/keep_daily/keep_weekly/keep_monthly are all 0 or unset); refusing to prune everything.",
+        )));
+    }
+
+    for t in &config.to {
+        let root = path::Path::new(t);
+        if !root.is_dir() {
+            continue;
+        }
+
+        let mut snapshots = list_snapshots(root, &config.prefix)?;
+        snapshots.sort_by(|a, b| b.1.cmp(&a.1));
+
+        let keep = select_snapshots_to_keep(&snapshots, &config.retention);
+
+        for (path, _) in &snapshots {
+            if keep.contains(path) {
+                continue;
+            }
+
+            if dry_run {
+                println!("Would remove {:?}", path);
+            } else {
+                println!("Removing {:?}", path);
+                fs::remove_dir_all(path)?;
+            }
+        }
+
+        // Index entries are only actually orphaned once the snapshots above
+        // are really removed, so --dry-run (which removes nothing) cannot
+        // show what gc_index would free without the prune having happened;
+        // don't pretend to simulate it.
+        if dry_run {
+            println!("(--dry-run does not simulate content-index garbage collection)");
+        } else {
+            gc_index(&root.join(".index"))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns a file's hard link count, so callers can tell whether anything
+/// besides the caller's own reference to it still exists.
+#[cfg(unix)]
+fn nlink(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+#[cfg(windows)]
+fn nlink(metadata: &fs::Metadata) -> u64 {
+    use std::os::windows::fs::MetadataExt;
+    metadata.number_of_links().unwrap_or(0) as u64
+}
+
+/// Removes content-addressable index entries that are no longer referenced
+/// by any surviving snapshot, i.e. whose only remaining hard link is the
+/// index entry itself. Without this, a file copied into the index during a
+/// backup (see `copy_file`) would keep its inode alive forever, even after
+/// every snapshot that ever referenced it was pruned.
+fn gc_index(index_root: &path::Path) -> Result<(), Box<error::Error>> {
+    if !index_root.is_dir() {
+        return Ok(());
+    }
+
+    for shard_entry in fs::read_dir(index_root)? {
+        let shard = shard_entry?.path();
+        if !shard.is_dir() {
+            continue;
+        }
+
+        for file_entry in fs::read_dir(&shard)? {
+            let file_entry = file_entry?;
+            let path = file_entry.path();
+
+            if nlink(&file_entry.metadata()?) <= 1 {
+                println!("Removing unreferenced index entry {:?}", path);
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+
     Ok(())
 }